@@ -0,0 +1,82 @@
+//! Clock abstraction that lets `RpmTimer`'s dispatch loop be driven deterministically in tests,
+//! instead of depending on real wall-clock time and real sleeps.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Abstracts the passage of time used by `RpmTimer`'s dispatch loop.
+pub trait Clock: Sync {
+    /// Returns the current instant, analogous to `Instant::now()`.
+    fn now(&self) -> Instant;
+
+    /// Pauses for `duration`. For a real clock this blocks the current thread; a test clock
+    /// may instead just advance its own notion of "now" without actually waiting.
+    fn sleep(&self, duration: Duration);
+
+    /// Async analogue of `sleep`, used by the `async`-feature `run_stream` path so it can pace
+    /// ticks without blocking the runtime's worker thread. The default implementation just calls
+    /// the blocking `sleep`, which is what test clocks (e.g. `ManualClock`) want, since advancing
+    /// their notion of "now" never actually blocks.
+    #[cfg(feature = "async")]
+    fn sleep_async(&self, duration: Duration) -> impl std::future::Future<Output = ()> + Send {
+        async move { self.sleep(duration) }
+    }
+}
+
+/// Real, monotonic wall-clock time. This is what `RpmTimer` uses by default.
+#[derive(Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    #[cfg(feature = "async")]
+    fn sleep_async(&self, duration: Duration) -> impl std::future::Future<Output = ()> + Send {
+        tokio::time::sleep(duration)
+    }
+}
+
+/// A clock that never advances on its own; it only moves forward when `sleep` is called or the
+/// test calls `advance` directly. This lets tests script exact tick durations and assert
+/// dispatch behavior (e.g. the `items_ready` accumulation math, burst clamping, or the
+/// `working_threads < pool_size` gating) without any real time passing.
+pub struct ManualClock {
+    now: Mutex<Instant>
+}
+
+impl ManualClock {
+    /// Creates a new `ManualClock` anchored at the current real instant.
+    pub fn new() -> Self {
+        Self { now: Mutex::new(Instant::now()) }
+    }
+
+    /// Moves the clock's notion of "now" forward by `duration`, without blocking.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+
+        *now += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -1,22 +1,17 @@
-use std::time::Instant;
-use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub trait InstantExtensions {
-    fn elapsed_seconds(&self) -> f64;
-}
-
-impl InstantExtensions for Instant {
-    fn elapsed_seconds(&self) -> f64 {
-        let elapsed = self.elapsed();
-
-        elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1000_000_000f64
-    }
+/// Converts a `Duration` to fractional seconds, e.g. for use in `rps_limit * seconds` math.
+pub fn duration_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1000_000_000f64
 }
 
 pub trait AtomicUsizeExtensions {
     fn increase(&self);
     fn decrease(&self);
+    fn increase_by(&self, amount: usize);
+    fn decrease_by(&self, amount: usize);
     fn get(&self) -> usize;
 }
 
@@ -29,7 +24,48 @@ impl AtomicUsizeExtensions for Arc<AtomicUsize> {
         self.fetch_sub(1, Ordering::SeqCst);
     }
 
+    fn increase_by(&self, amount: usize) {
+        self.fetch_add(amount, Ordering::SeqCst);
+    }
+
+    fn decrease_by(&self, amount: usize) {
+        self.fetch_sub(amount, Ordering::SeqCst);
+    }
+
     fn get(&self) -> usize {
         self.load(Ordering::SeqCst)
     }
 }
+
+/// Shared "freeze until" marker used to pause dispatching without losing it between threads.
+pub trait FrozenUntilExtensions {
+    /// Freezes dispatching until `until`, unless it is already frozen past that point.
+    fn freeze_until(&self, until: Instant);
+
+    /// Returns how long dispatching should still be paused for (as of `now`), or `None` if it
+    /// isn't frozen. `now` is passed in rather than read from the wall clock so callers can
+    /// drive it from an injected `Clock`.
+    fn remaining(&self, now: Instant) -> Option<Duration>;
+}
+
+impl FrozenUntilExtensions for Arc<Mutex<Option<Instant>>> {
+    fn freeze_until(&self, until: Instant) {
+        let mut frozen_until = self.lock().unwrap();
+
+        if frozen_until.is_none_or(|current| until > current) {
+            *frozen_until = Some(until);
+        }
+    }
+
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        let frozen_until = self.lock().unwrap();
+
+        frozen_until.and_then(|until| {
+            if until > now {
+                Some(until - now)
+            } else {
+                None
+            }
+        })
+    }
+}
@@ -145,16 +145,35 @@
 
 extern crate scoped_pool;
 extern crate num_cpus;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate tokio;
 
 mod helpers;
+mod clock;
+#[cfg(feature = "async")]
+mod stream;
 
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use std::cmp::min;
-use std::thread::sleep;
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicUsize};
 use scoped_pool::Pool;
 use self::helpers::*;
+pub use self::clock::{Clock, RealClock, ManualClock};
+
+/// Outcome of processing one batch of items, returned by the action passed to
+/// [`run_slice_retry`](struct.RpmTimer.html#method.run_slice_retry).
+pub enum ProcessOutcome<T> {
+    /// All items in the batch were processed successfully.
+    Done,
+    /// The downstream API pushed back (e.g. an HTTP 429 with `Retry-After`). Dispatching
+    /// freezes for the given `Duration` and `items` are retried at the front of the next
+    /// batch instead of being dropped.
+    RetryAfter(Duration, Vec<T>)
+}
 
 /// Use this struct to limit the speed of any items processing.
 ///
@@ -182,13 +201,33 @@ use self::helpers::*;
 ///     }
 /// }
 /// ```
-pub struct RpmTimer {
+pub struct RpmTimer<C: Clock = RealClock> {
     tick: Duration,
     rps_limit: f64,
-    max_threads: Option<usize> //None == number of cpus
+    max_threads: Option<usize>, //None == number of cpus
+    burst_limit: Option<f64>, //None == unbounded
+    ramp_up: Option<Duration>, //None == no ramp-up, start at rps_limit immediately
+    max_in_flight_items: Option<usize>, //None == unbounded
+    clock: C
 }
 
-impl RpmTimer {
+impl<C: Clock> RpmTimer<C> {
+    /// Creates a timer driven by the given `Clock` instead of real wall-clock time.
+    ///
+    /// This is mainly useful in tests: pass a `ManualClock` to script tick durations and assert
+    /// dispatch behavior deterministically, without any real sleeping.
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            tick:                Duration::from_millis(100),
+            rps_limit:           1f64,
+            max_threads:         None,
+            burst_limit:         None,
+            ramp_up:             None,
+            max_in_flight_items: None,
+            clock
+        }
+    }
+
     /// Main thread will try to spawn working threads every _tick_.
     ///
     /// Tip: yhe higher RPM requested, the lower tick duration should be.
@@ -224,6 +263,55 @@ impl RpmTimer {
         self
     }
 
+    /// Maximum number of items that `items_ready` may accumulate to.
+    ///
+    /// Without a cap, a long stall on the main thread (e.g. all worker threads busy for a
+    /// while) lets `items_ready` grow without bound, so the next free worker is handed a huge
+    /// burst the instant it's available. This clamps that burst to a sane bucket size, much
+    /// like the allowance cap in a token-bucket rate limiter.
+    ///
+    /// Pass `None` to leave `items_ready` unbounded, i.e. the pre-`burst_limit` behavior.
+    ///
+    /// Default: None
+    pub fn burst_limit<T: Into<Option<f64>>>(mut self, value: T) -> Self {
+        self.burst_limit = value.into();
+        self
+    }
+
+    /// Gradually ramps the effective rate up from 0 to `rps_limit` over `value`, instead of
+    /// dispatching at full `rps_limit` from the very first tick.
+    ///
+    /// While the time elapsed since `run` started is within the ramp-up window, the effective
+    /// rate used to accumulate `items_ready` is `rps_limit * (elapsed / value)`; once past the
+    /// window it holds steady at the full `rps_limit`. This smooths out the opening burst, which
+    /// is useful when the target API trips connection-burst protections on a cold start.
+    ///
+    /// Pass `None` to disable ramp-up and start at the full `rps_limit` immediately.
+    ///
+    /// Default: None
+    pub fn ramp_up<T: Into<Option<Duration>>>(mut self, value: T) -> Self {
+        self.ramp_up = value.into();
+        self
+    }
+
+    /// Caps the total number of items owned by in-flight batches (i.e. dispatched to a worker
+    /// but not yet finished) to at most `value`.
+    ///
+    /// Without this, a slow action combined with a huge source iterator can leave many items
+    /// sitting in worker `Vec`s: up to `max_threads` batches are in flight at once, and each one
+    /// holds every item that accumulated in `items_ready` while the workers were busy. Once the
+    /// cap is reached, this tick's batch is shrunk to whatever capacity remains (possibly to
+    /// nothing), which applies backpressure to the iterator until a worker finishes and frees
+    /// up room.
+    ///
+    /// Pass `None` to leave the number of in-flight items unbounded.
+    ///
+    /// Default: None
+    pub fn max_in_flight_items<T: Into<Option<usize>>>(mut self, value: T) -> Self {
+        self.max_in_flight_items = value.into();
+        self
+    }
+
     /// Non-allocating method that spawns thread and pass sub-slices to the workers.
     ///
     /// This is the preffered way unless you only have an iterator.
@@ -262,6 +350,176 @@ impl RpmTimer {
         });
     }
 
+    /// Like `run_slice`, but `action` returns a `Vec<R>` of results for its batch instead of
+    /// `()`, and those results are collected and returned once every batch has finished.
+    ///
+    /// Results are returned in the same order the items were dispatched in, regardless of the
+    /// order in which batches actually finish processing across the pool.
+    ///
+    /// It waits for all spawned threads to finish.
+    pub fn run_slice_map<T, R, F>(self, items: &[T], action: F) -> Vec<R>
+        where F: Fn(&[T]) -> Vec<R> + Sync,
+              T: Send + Sync,
+              R: Send
+    {
+        let mut last_dispatched_item_index = 0;
+
+        self.run_map(action, |items_to_dispatch| {
+            let first_item_index_to_process = last_dispatched_item_index;
+
+            last_dispatched_item_index = min(last_dispatched_item_index + items_to_dispatch, items.len());
+
+            (&items[first_item_index_to_process..last_dispatched_item_index], last_dispatched_item_index == items.len())
+        })
+    }
+
+    /// Like `run_iter`, but `action` returns a `Vec<R>` of results for its batch instead of
+    /// `()`, and those results are collected and returned once every batch has finished.
+    ///
+    /// Results are returned in the same order the items were dispatched in, regardless of the
+    /// order in which batches actually finish processing across the pool.
+    ///
+    /// It waits for all spawned threads to finish.
+    pub fn run_iter_map<T, I, R, F>(self, mut items: I, action: F) -> Vec<R>
+        where F: Fn(Vec<T>) -> Vec<R> + Sync,
+              I: Iterator<Item=T>,
+              T: Send,
+              R: Send
+    {
+        self.run_map(action, |items_to_dispatch| {
+            let items_to_process = items.by_ref().take(items_to_dispatch).collect::<Vec<_>>();
+            let len = items_to_process.len();
+
+            (items_to_process, len != items_to_dispatch)
+        })
+    }
+
+    /// Like `run_slice`, but lets the processing function push back on a batch by returning
+    /// `ProcessOutcome::RetryAfter(duration, items)` (e.g. in response to a 429 / Retry-After
+    /// from a rate-limited API) instead of dropping the items it failed to process.
+    ///
+    /// When any worker reports `RetryAfter`, dispatching freezes for the reported duration and
+    /// the failed items are re-enqueued at the front of the next batch so they get retried.
+    ///
+    /// `ramp_up` and `max_in_flight_items` are honored the same way they are on `run`/`run_map`.
+    ///
+    /// It waits for all spawned threads to finish.
+    pub fn run_slice_retry<T, F>(self, items: &[T], action: F)
+        where F: Fn(&[T]) -> ProcessOutcome<T> + Sync,
+              T: Send + Sync + Clone
+    {
+        let pool_size            = self.max_threads.unwrap_or_else(|| num_cpus::get());
+        let pool                 = Pool::new(pool_size);
+        let working_threads      = Arc::new(AtomicUsize::new(0));
+        let in_flight_items      = Arc::new(AtomicUsize::new(0));
+        let frozen_until         = Arc::new(Mutex::new(None));
+        let retry_queue          = Arc::new(Mutex::new(VecDeque::new()));
+        let start_time           = self.clock.now();
+        let mut last_tick_time   = start_time;
+        let mut items_ready      = 1f64;
+        let mut next_item_index  = 0;
+        let a                    = &action;
+        let clock                = &self.clock;
+
+        pool.scoped(|scope|
+            while next_item_index < items.len() || !retry_queue.lock().unwrap().is_empty() || working_threads.get() > 0 {
+                let tick_start_time = self.clock.now();
+                // Sampled before the freeze check: a worker records its freeze before it frees
+                // its thread, so a free thread seen here means that freeze is also seen below.
+                let has_free_worker = working_threads.get() < pool_size;
+
+                if let Some(remaining) = frozen_until.remaining(tick_start_time) {
+                    // Reset the accumulation baseline on every frozen tick, so that once the
+                    // freeze lifts, `items_ready` resumes climbing from the current tick instead
+                    // of crediting the entire frozen span in one lump sum.
+                    last_tick_time = tick_start_time;
+
+                    self.clock.sleep(min(remaining, self.tick));
+                    continue;
+                }
+
+                if has_free_worker {
+                    let seconds_since_last_tick = duration_seconds(tick_start_time - last_tick_time);
+
+                    last_tick_time = tick_start_time;
+
+                    let effective_rps = match self.ramp_up {
+                        Some(ramp_up) if tick_start_time - start_time < ramp_up => {
+                            self.rps_limit * duration_seconds(tick_start_time - start_time) / duration_seconds(ramp_up)
+                        },
+                        _ => self.rps_limit
+                    };
+
+                    items_ready += effective_rps * seconds_since_last_tick;
+
+                    if let Some(burst_cap) = self.burst_limit {
+                        items_ready = items_ready.min(burst_cap);
+                    }
+
+                    let items_to_take = items_ready.floor() as usize;
+
+                    let items_to_take = match self.max_in_flight_items {
+                        Some(cap) => items_to_take.min(cap.saturating_sub(in_flight_items.get())),
+                        None      => items_to_take
+                    };
+
+                    if items_to_take > 0 {
+                        let mut batch = Vec::with_capacity(items_to_take);
+
+                        {
+                            let mut retry_queue = retry_queue.lock().unwrap();
+
+                            while batch.len() < items_to_take {
+                                match retry_queue.pop_front() {
+                                    Some(item) => batch.push(item),
+                                    None       => break
+                                }
+                            }
+                        }
+
+                        while batch.len() < items_to_take && next_item_index < items.len() {
+                            batch.push(items[next_item_index].clone());
+                            next_item_index += 1;
+                        }
+
+                        if !batch.is_empty() {
+                            let working_threads_clone = working_threads.clone();
+                            let in_flight_items_clone = in_flight_items.clone();
+                            let frozen_until_clone    = frozen_until.clone();
+                            let retry_queue_clone     = retry_queue.clone();
+                            let batch_len             = batch.len();
+
+                            items_ready -= batch_len as f64;
+
+                            in_flight_items.increase_by(batch_len);
+
+                            working_threads.increase();
+
+                            scope.execute(move || {
+                                match a(&batch) {
+                                    ProcessOutcome::Done => {},
+                                    ProcessOutcome::RetryAfter(duration, failed_items) => {
+                                        frozen_until_clone.freeze_until(clock.now() + duration);
+                                        retry_queue_clone.lock().unwrap().extend(failed_items);
+                                    }
+                                }
+
+                                working_threads_clone.decrease();
+                                in_flight_items_clone.decrease_by(batch_len);
+                            });
+                        }
+                    }
+                }
+
+                let tick_elapsed = clock.now() - tick_start_time;
+
+                if tick_elapsed < self.tick {
+                    self.clock.sleep(self.tick - tick_elapsed);
+                }
+            }
+        );
+    }
+
     fn run<TItems, FAction, FTake>(self, action: FAction, mut take: FTake)
         where FAction: Fn(TItems) + Sync,
               FTake: FnMut(usize) -> (TItems, bool),
@@ -270,51 +528,318 @@ impl RpmTimer {
         let pool_size          = self.max_threads.unwrap_or_else(|| num_cpus::get());
         let pool               = Pool::new(pool_size);
         let working_threads    = Arc::new(AtomicUsize::new(0));
-        let mut last_tick_time = Instant::now();
+        let in_flight_items    = Arc::new(AtomicUsize::new(0));
+        let start_time         = self.clock.now();
+        let mut last_tick_time = start_time;
         let mut items_ready    = 1f64;
         let mut finished       = false;
 
         pool.scoped(|scope|
             while !finished {
-                let tick_start_time = Instant::now();
+                let tick_start_time = self.clock.now();
 
                 if working_threads.get() < pool_size {
-                    let seconds_since_last_tick = last_tick_time.elapsed_seconds();
+                    let seconds_since_last_tick = duration_seconds(tick_start_time - last_tick_time);
+
+                    last_tick_time = tick_start_time;
 
-                    last_tick_time  = tick_start_time;
-                    items_ready    += self.rps_limit * seconds_since_last_tick;
+                    let effective_rps = match self.ramp_up {
+                        Some(ramp_up) if tick_start_time - start_time < ramp_up => {
+                            self.rps_limit * duration_seconds(tick_start_time - start_time) / duration_seconds(ramp_up)
+                        },
+                        _ => self.rps_limit
+                    };
+
+                    items_ready += effective_rps * seconds_since_last_tick;
+
+                    if let Some(burst_cap) = self.burst_limit {
+                        items_ready = items_ready.min(burst_cap);
+                    }
 
                     let items_to_take = items_ready.floor() as usize;
 
+                    let items_to_take = match self.max_in_flight_items {
+                        Some(cap) => items_to_take.min(cap.saturating_sub(in_flight_items.get())),
+                        None      => items_to_take
+                    };
+
                     if items_to_take > 0 {
                         let (taken_items, is_finished) = take(items_to_take);
                         let working_threads_clone      = working_threads.clone();
+                        let in_flight_items_clone      = in_flight_items.clone();
                         let a = &action;
 
                         finished     = is_finished;
                         items_ready -= items_to_take as f64;
 
+                        in_flight_items.increase_by(items_to_take);
+
                         working_threads.increase();
 
                         scope.execute(move || {
                             a(taken_items);
                             working_threads_clone.decrease();
+                            in_flight_items_clone.decrease_by(items_to_take);
+                        });
+                    }
+                }
+
+                let tick_elapsed = self.clock.now() - tick_start_time;
+
+                if tick_elapsed < self.tick {
+                    self.clock.sleep(self.tick - tick_elapsed);
+                }
+            }
+        );
+    }
+
+    fn run_map<TItems, R, FAction, FTake>(self, action: FAction, mut take: FTake) -> Vec<R>
+        where FAction: Fn(TItems) -> Vec<R> + Sync,
+              FTake: FnMut(usize) -> (TItems, bool),
+              TItems: Send,
+              R: Send
+    {
+        let pool_size          = self.max_threads.unwrap_or_else(|| num_cpus::get());
+        let pool               = Pool::new(pool_size);
+        let working_threads    = Arc::new(AtomicUsize::new(0));
+        let in_flight_items    = Arc::new(AtomicUsize::new(0));
+        let results            = Arc::new(Mutex::new(Vec::new()));
+        let start_time         = self.clock.now();
+        let mut last_tick_time = start_time;
+        let mut items_ready    = 1f64;
+        let mut finished       = false;
+        let mut next_sequence  = 0usize;
+
+        pool.scoped(|scope|
+            while !finished {
+                let tick_start_time = self.clock.now();
+
+                if working_threads.get() < pool_size {
+                    let seconds_since_last_tick = duration_seconds(tick_start_time - last_tick_time);
+
+                    last_tick_time = tick_start_time;
+
+                    let effective_rps = match self.ramp_up {
+                        Some(ramp_up) if tick_start_time - start_time < ramp_up => {
+                            self.rps_limit * duration_seconds(tick_start_time - start_time) / duration_seconds(ramp_up)
+                        },
+                        _ => self.rps_limit
+                    };
+
+                    items_ready += effective_rps * seconds_since_last_tick;
+
+                    if let Some(burst_cap) = self.burst_limit {
+                        items_ready = items_ready.min(burst_cap);
+                    }
+
+                    let items_to_take = items_ready.floor() as usize;
+
+                    let items_to_take = match self.max_in_flight_items {
+                        Some(cap) => items_to_take.min(cap.saturating_sub(in_flight_items.get())),
+                        None      => items_to_take
+                    };
+
+                    if items_to_take > 0 {
+                        let (taken_items, is_finished) = take(items_to_take);
+                        let working_threads_clone      = working_threads.clone();
+                        let in_flight_items_clone      = in_flight_items.clone();
+                        let results_clone              = results.clone();
+                        let sequence                   = next_sequence;
+                        let a = &action;
+
+                        finished      = is_finished;
+                        items_ready  -= items_to_take as f64;
+                        next_sequence += 1;
+
+                        in_flight_items.increase_by(items_to_take);
+
+                        working_threads.increase();
+
+                        scope.execute(move || {
+                            let batch_results = a(taken_items);
+
+                            results_clone.lock().unwrap().push((sequence, batch_results));
+
+                            working_threads_clone.decrease();
+                            in_flight_items_clone.decrease_by(items_to_take);
                         });
                     }
                 }
 
-                sleep(self.tick - tick_start_time.elapsed());
+                let tick_elapsed = self.clock.now() - tick_start_time;
+
+                if tick_elapsed < self.tick {
+                    self.clock.sleep(self.tick - tick_elapsed);
+                }
             }
         );
+
+        let mut results = Arc::try_unwrap(results)
+            .ok()
+            .expect("all dispatched batches finished by the time pool.scoped returned")
+            .into_inner()
+            .unwrap();
+
+        results.sort_by_key(|(sequence, _)| *sequence);
+
+        results.into_iter().flat_map(|(_, batch_results)| batch_results).collect()
     }
 }
 
-impl Default for RpmTimer {
+impl Default for RpmTimer<RealClock> {
     fn default() -> Self {
-        Self {
-            tick:        Duration::from_millis(100),
-            rps_limit:   1f64,
-            max_threads: None
-        }
+        Self::with_clock(RealClock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn burst_limit_caps_every_dispatched_batch() {
+        let dispatched       = Arc::new(Mutex::new(Vec::new()));
+        let dispatched_clone = dispatched.clone();
+        let items            = (0..50).collect::<Vec<_>>();
+
+        RpmTimer::with_clock(ManualClock::new())
+            .tick(Duration::from_millis(10))
+            .rps_limit(1000.0)
+            .burst_limit(5.0)
+            .max_threads(4)
+            .run_slice(&items, move |batch: &[i32]| {
+                dispatched_clone.lock().unwrap().push(batch.len());
+            });
+
+        let dispatched = dispatched.lock().unwrap();
+
+        assert_eq!(dispatched.iter().sum::<usize>(), items.len());
+        assert!(dispatched.iter().all(|&size| size <= 5));
+    }
+
+    #[test]
+    fn ramp_up_holds_back_the_first_batches() {
+        let dispatched       = Arc::new(Mutex::new(Vec::new()));
+        let dispatched_clone = dispatched.clone();
+        let items            = (0..10).collect::<Vec<_>>();
+        let clock            = ManualClock::new();
+
+        RpmTimer::with_clock(clock)
+            .tick(Duration::from_millis(100))
+            .rps_limit(10.0)
+            .ramp_up(Duration::from_millis(500))
+            .max_threads(1)
+            .run_slice(&items, move |batch: &[i32]| {
+                dispatched_clone.lock().unwrap().push(batch.len());
+            });
+
+        let dispatched = dispatched.lock().unwrap();
+
+        // The first batch is the single item `items_ready` starts with. Over the 500ms ramp-up
+        // window (5 ticks of 100ms, rps climbing from 0 to 10) further items accumulate more
+        // slowly than the 5 the full rate would add over the same 500ms.
+        assert!(dispatched[0] < 10);
+        assert_eq!(dispatched.iter().sum::<usize>(), items.len());
+    }
+
+    #[test]
+    fn max_in_flight_items_holds_back_dispatch_until_a_worker_frees_up() {
+        let dispatched            = Arc::new(Mutex::new(Vec::new()));
+        let dispatched_clone      = dispatched.clone();
+        let release               = Arc::new(AtomicBool::new(false));
+        let release_clone         = release.clone();
+        let in_flight             = Arc::new(AtomicUsize::new(0));
+        let in_flight_clone       = in_flight.clone();
+        let max_observed_in_flight = Arc::new(AtomicUsize::new(0));
+        let max_observed_clone    = max_observed_in_flight.clone();
+        let items                 = (0..6).collect::<Vec<_>>();
+        let items_len             = items.len();
+
+        let controller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            release_clone.store(true, Ordering::SeqCst);
+        });
+
+        let dispatched_clone = dispatched.clone();
+
+        RpmTimer::with_clock(ManualClock::new())
+            .tick(Duration::from_millis(10))
+            .rps_limit(1000.0)
+            .burst_limit(2.0)
+            .max_threads(4)
+            .max_in_flight_items(2)
+            .run_iter(items.into_iter(), move |batch: Vec<i32>| {
+                let now = in_flight_clone.fetch_add(batch.len(), Ordering::SeqCst) + batch.len();
+
+                max_observed_clone.fetch_max(now, Ordering::SeqCst);
+                dispatched_clone.lock().unwrap().push(batch.len());
+
+                while !release.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+
+                in_flight_clone.fetch_sub(batch.len(), Ordering::SeqCst);
+            });
+
+        controller.join().unwrap();
+
+        let dispatched = dispatched.lock().unwrap();
+
+        // The cap must never let more than 2 items be in flight at once, no matter how many
+        // separate batches they're split across.
+        assert_eq!(max_observed_in_flight.load(Ordering::SeqCst), 2);
+        assert_eq!(dispatched.iter().sum::<usize>(), items_len);
+    }
+
+    #[test]
+    fn run_slice_retry_freezes_without_crediting_the_whole_frozen_span() {
+        let dispatched       = Arc::new(Mutex::new(Vec::new()));
+        let dispatched_clone = dispatched.clone();
+        let call_count       = Arc::new(AtomicUsize::new(0));
+        let call_count_clone = call_count.clone();
+        let items            = (0..5).collect::<Vec<_>>();
+
+        RpmTimer::with_clock(ManualClock::new())
+            .tick(Duration::from_millis(100))
+            .rps_limit(1.0)
+            .max_threads(1)
+            .run_slice_retry(&items, move |batch: &[i32]| {
+                dispatched_clone.lock().unwrap().push(batch.to_vec());
+
+                if call_count_clone.fetch_add(1, Ordering::SeqCst) == 0 {
+                    ProcessOutcome::RetryAfter(Duration::from_millis(500), batch.to_vec())
+                } else {
+                    ProcessOutcome::Done
+                }
+            });
+
+        let dispatched = dispatched.lock().unwrap();
+
+        // Had the freeze not reset the accumulation baseline, the first tick after the 500ms
+        // freeze lifts would credit the whole freeze (plus the time the worker was busy before
+        // reporting it) in one lump sum, so the retried item could go out together with the
+        // items after it. Instead accumulation restarts from that tick, and at 1 rps the retried
+        // item comes back on its own.
+        assert_eq!(dispatched[1], vec![0]);
+        assert_eq!(dispatched.iter().flatten().collect::<std::collections::HashSet<_>>().len(), items.len());
+    }
+
+    #[test]
+    fn run_slice_map_returns_results_in_dispatch_order() {
+        let items = (0..50).collect::<Vec<_>>();
+
+        let results = RpmTimer::with_clock(ManualClock::new())
+            .tick(Duration::from_millis(10))
+            .rps_limit(1000.0)
+            .burst_limit(5.0)
+            .max_threads(4)
+            .run_slice_map(&items, |batch: &[i32]| {
+                batch.iter().map(|item| item * 2).collect()
+            });
+
+        assert_eq!(results, items.iter().map(|item| item * 2).collect::<Vec<_>>());
     }
 }
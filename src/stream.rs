@@ -0,0 +1,150 @@
+//! Async execution mode, enabled via the `async` feature.
+//!
+//! `run_stream` drives the same tick-based dispatch loop as `run`/`run_iter`, but on an async
+//! runtime instead of a thread pool: concurrency is bounded by a semaphore of `max_threads`
+//! permits instead of `scoped_pool::Pool`, and ticks are paced with a non-blocking sleep. This
+//! lets callers limit thousands of concurrent async requests (e.g. to an HTTP client) from a
+//! single task instead of spending one OS thread per in-flight batch.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use futures::stream::{Stream, StreamExt};
+use tokio::sync::Semaphore;
+
+use super::{Clock, RpmTimer};
+use super::helpers::{duration_seconds, AtomicUsizeExtensions};
+
+impl<C: Clock> RpmTimer<C> {
+    /// Async analogue of `run_iter`: collects items from `stream` in portions and passes every
+    /// portion to `action`, pacing dispatch the same way `run`/`run_iter` do.
+    ///
+    /// Concurrency is bounded by a semaphore of `max_threads` permits (default: number of cpus)
+    /// instead of a thread pool, so each in-flight batch costs a permit instead of a thread.
+    ///
+    /// `ramp_up` and `max_in_flight_items` are honored the same way they are on `run`/`run_map`.
+    ///
+    /// Waits for every dispatched batch to finish before returning.
+    pub async fn run_stream<S, F, Fut>(self, mut stream: S, action: F)
+        where S: Stream + Unpin,
+              S::Item: Send + 'static,
+              F: Fn(Vec<S::Item>) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output=()> + Send + 'static
+    {
+        let max_permits        = self.max_threads.unwrap_or_else(num_cpus::get);
+        let semaphore          = Arc::new(Semaphore::new(max_permits));
+        let in_flight_items    = Arc::new(AtomicUsize::new(0));
+        let action             = Arc::new(action);
+        let start_time         = self.clock.now();
+        let mut last_tick_time = start_time;
+        let mut items_ready    = 1f64;
+        let mut finished       = false;
+        let mut handles        = Vec::new();
+
+        while !finished {
+            let tick_start_time = self.clock.now();
+
+            if semaphore.available_permits() > 0 {
+                let seconds_since_last_tick = duration_seconds(tick_start_time - last_tick_time);
+
+                last_tick_time = tick_start_time;
+
+                let effective_rps = match self.ramp_up {
+                    Some(ramp_up) if tick_start_time - start_time < ramp_up => {
+                        self.rps_limit * duration_seconds(tick_start_time - start_time) / duration_seconds(ramp_up)
+                    },
+                    _ => self.rps_limit
+                };
+
+                items_ready += effective_rps * seconds_since_last_tick;
+
+                if let Some(burst_cap) = self.burst_limit {
+                    items_ready = items_ready.min(burst_cap);
+                }
+
+                let items_to_take = items_ready.floor() as usize;
+
+                let items_to_take = match self.max_in_flight_items {
+                    Some(cap) => items_to_take.min(cap.saturating_sub(in_flight_items.get())),
+                    None      => items_to_take
+                };
+
+                if items_to_take > 0 {
+                    let mut batch = Vec::with_capacity(items_to_take);
+
+                    while batch.len() < items_to_take {
+                        match stream.next().await {
+                            Some(item) => batch.push(item),
+                            None       => { finished = true; break; }
+                        }
+                    }
+
+                    if !batch.is_empty() {
+                        let batch_len = batch.len();
+
+                        items_ready -= batch_len as f64;
+
+                        in_flight_items.increase_by(batch_len);
+
+                        // Acquire before spawning, release (via drop) when the batch future completes,
+                        // in place of the `working_threads` atomic used by the thread-pool based `run`.
+                        let permit             = semaphore.clone().acquire_owned().await.expect("semaphore closed");
+                        let action             = action.clone();
+                        let in_flight_items    = in_flight_items.clone();
+
+                        handles.push(tokio::spawn(async move {
+                            action(batch).await;
+                            in_flight_items.decrease_by(batch_len);
+                            drop(permit);
+                        }));
+                    }
+                }
+            }
+
+            let elapsed = self.clock.now() - tick_start_time;
+
+            if elapsed < self.tick {
+                self.clock.sleep_async(self.tick - elapsed).await;
+            }
+        }
+
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use futures::stream;
+
+    use super::super::{ManualClock, RpmTimer};
+
+    #[tokio::test]
+    async fn run_stream_paces_dispatch_using_the_injected_clock() {
+        let dispatched       = Arc::new(Mutex::new(Vec::new()));
+        let dispatched_clone = dispatched.clone();
+        let items            = (0..10).collect::<Vec<_>>();
+
+        RpmTimer::with_clock(ManualClock::new())
+            .tick(Duration::from_millis(10))
+            .rps_limit(1000.0)
+            .burst_limit(3.0)
+            .max_threads(4)
+            .run_stream(stream::iter(items.clone()), move |batch: Vec<i32>| {
+                let dispatched_clone = dispatched_clone.clone();
+
+                async move {
+                    dispatched_clone.lock().unwrap().push(batch);
+                }
+            })
+            .await;
+
+        let dispatched = dispatched.lock().unwrap();
+
+        assert!(dispatched.iter().all(|batch| batch.len() <= 3));
+        assert_eq!(dispatched.iter().flatten().copied().collect::<Vec<_>>().len(), items.len());
+    }
+}